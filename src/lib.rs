@@ -1,22 +1,48 @@
 #![feature(box_syntax)]
+#![cfg_attr(test, feature(test))]
 
 extern crate servo;
+#[macro_use]
+extern crate bitflags;
+extern crate futures;
 extern crate gleam;
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate test;
 
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
 use gleam::gl;
 
 use self::servo::Servo;
 use self::servo::compositing::windowing::WindowMethods;
 use self::servo::euclid::{Point2D, Size2D, TypedPoint2D, TypedRect, ScaleFactor, TypedSize2D};
 use self::servo::ipc_channel::ipc::IpcSender;
-use self::servo::net_traits::net_error_list::NetError;
+use self::servo::servo_config::opts;
+use self::servo::servo_config::prefs::{PREFS, PrefValue as ServoPrefValue, read_prefs_from_file};
 use self::servo::servo_config::resource_files::set_resources_path;
 use self::servo::servo_geometry::DeviceIndependentPixel;
-use self::servo::script_traits::{DevicePixel, LoadData};
+use self::servo::script_traits::{DevicePixel, LoadData, TouchId};
 
+use std::borrow::Cow;
 use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 
 use self::servo::msg::constellation_msg;
@@ -27,30 +53,332 @@ pub use self::servo::msg::constellation_msg::{ALT, CONTROL, NONE, SHIFT, SUPER};
 pub use self::servo::config::servo_version;
 pub use self::servo::compositing::windowing::{MouseWindowEvent, WindowNavigateMsg, WindowEvent};
 pub use self::servo::servo_url::ServoUrl;
+pub use self::servo::net_traits::net_error_list::NetError as NetErrorKind;
 pub use self::servo::style_traits::cursor::Cursor;
 pub use self::servo::script_traits::{MouseButton, TouchEventType};
 pub use self::servo::webrender_traits::ScrollLocation;
 pub use self::servo::compositing::compositor_thread::EventLoopWaker;
 
-#[derive(Debug)]
+// Servo reads its options once, when the first compositor is created.
+// Anything set after that point would be silently ignored.
+static OPTS_FROZEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+// Headers the network stack controls, from the Fetch spec.
+const FORBIDDEN_HEADERS: &'static [&'static str] = &[
+    "accept-charset", "accept-encoding", "access-control-request-headers",
+    "access-control-request-method", "connection", "content-length", "cookie",
+    "cookie2", "date", "dnt", "expect", "host", "keep-alive", "origin", "referer",
+    "te", "trailer", "transfer-encoding", "upgrade", "via",
+];
+
+const RUN_UNTIL_POLL_INTERVAL_MS: u64 = 5;
+const SHUTDOWN_POLL_INTERVAL_MS: u64 = 1;
+
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 5.0;
+const ZOOM_STEP: f32 = 1.1;
+
+// Same bounds the compositor applies to the viewport zoom.
+const MIN_PINCH_ZOOM: f32 = 1.0;
+const MAX_PINCH_ZOOM: f32 = 10.0;
+
+// Servo doesn't report load progress. LoadProgress eases towards
+// MAX_LOAD_PROGRESS over time and only reaches 1.0 once the load ends.
+const MAX_LOAD_PROGRESS: f32 = 0.9;
+const LOAD_PROGRESS_TIME_CONSTANT: f32 = 2.0;
+const LOAD_PROGRESS_STEP: f32 = 0.05;
+
+// Cloning an event that carries an IpcSender clones the sender: replying
+// through either copy answers Servo, and only the first reply counts.
+#[derive(Debug, Clone)]
 pub enum BrowserEvent {
     SetWindowInnerSize(BrowserId, u32, u32),
     SetWindowPosition(BrowserId, i32, i32),
     SetFullScreenState(BrowserId, bool),
     TitleChanged(BrowserId, Option<String>),
     StatusChanged(BrowserId, Option<String>),
+    // None when the pointer leaves the link. Servo doesn't report hovered
+    // links to embedders yet, so nothing sends this.
+    LinkHovered(BrowserId, Option<ServoUrl>),
     LoadStart(BrowserId),
     LoadEnd(BrowserId),
-    LoadError(BrowserId, String),
+    LoadProgress(BrowserId, f32),
+    LoadError(BrowserId, NetErrorKind, String),
     HeadParsed(BrowserId),
-    HistoryChanged(BrowserId, Vec<LoadData>, usize),
+    HistoryChanged(BrowserId, Vec<HistoryEntry>, usize),
     CursorChanged(Cursor),
     FaviconChanged(BrowserId, ServoUrl),
     Key(Option<BrowserId>, Option<char>, Key, constellation_msg::KeyModifiers),
     AllowNavigation(BrowserId, ServoUrl, IpcSender<bool>),
+    // Servo doesn't let embedders override certificate errors yet, so
+    // nothing sends this for now.
+    CertificateError(BrowserId, ServoUrl, CertInfo, IpcSender<bool>),
+    // Servo answers HTTP authentication challenges itself for now, so
+    // nothing sends this yet.
+    AuthRequired(BrowserId, AuthChallenge, IpcSender<Option<Credentials>>),
+    // Not sent yet, see accept_download.
+    DownloadRequested(BrowserId, DownloadInfo),
+    // Not sent yet, see find_in_page.
+    FindResult(BrowserId, usize, usize),
+    // Servo doesn't report media playback yet, so nothing sends this.
+    MediaStateChanged(BrowserId, bool),
+    // Servo doesn't ask the embedder for permissions yet, so nothing
+    // sends this for now.
+    PermissionRequest(BrowserId, PermissionKind, ServoUrl, IpcSender<PermissionDecision>),
+    // Servo handles right clicks itself for now, so nothing sends this
+    // yet.
+    ContextMenu(BrowserId, ContextMenuInfo),
+    // Not sent yet, see ConstellationBuilder::enable_devtools.
+    DevtoolsServerStarted(u16),
+    // A frame was composited and presented. Servo calls the EventLoopWaker
+    // when it has work to do, and perform_updates only needs to run then:
+    // this event tells whether that work produced new content.
+    NewFrameReady(BrowserId),
+    // Reload the browser to recover. Servo doesn't report pipeline
+    // failures to embedders yet, so nothing sends this.
+    RendererCrashed(BrowserId, CrashInfo),
+    // Script called window.close(). Nothing happens unless the embedder
+    // calls close_browser. Servo closes the browser itself for now, so
+    // nothing sends this yet.
+    CloseRequested(BrowserId),
+    // Scrollable size of the page, in CSS pixels. Servo doesn't report it
+    // to embedders yet, so nothing sends this.
+    ContentSizeChanged(BrowserId, f32, f32),
+    // Offset of the viewport in the page, in logical pixels. Not sent yet,
+    // see Compositor::scroll_position.
+    ScrollPositionChanged(BrowserId, f32, f32),
+    // Not sent yet, see Compositor::set_network_logging.
+    NetworkEvent(BrowserId, NetEvent),
+    // make_current failed and nothing is composited anymore. Sent once,
+    // until a composite succeeds again. See reinitialize_gl.
+    GlContextLost,
+    // Bounds (x, y, width, height) of the focused editable field, in
+    // logical pixels, to place the IME window. None once it loses focus.
+    // Servo doesn't tell embedders about focused fields yet, so nothing
+    // sends this.
+    ImeStateChanged(BrowserId, Option<(f32, f32, f32, f32)>),
+    // The page has a beforeunload handler. Reply false to stay on the
+    // page. Servo runs beforeunload handlers without asking the embedder
+    // yet, so nothing sends this.
+    BeforeUnload(BrowserId, Option<String>, IpcSender<bool>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetEvent {
+    RequestStart { url: ServoUrl, method: String },
+    ResponseHeaders { url: ServoUrl, status: u16 },
+    RequestComplete { url: ServoUrl, bytes: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashInfo {
+    pub message: String,
+    // What was loading when the pipeline failed, if known.
+    pub url: Option<ServoUrl>,
+}
+
+// What was under the pointer. `link_url` and `image_src` are None when
+// the click didn't hit a link or an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextMenuInfo {
+    pub position: (f32, f32),
+    pub link_url: Option<ServoUrl>,
+    pub image_src: Option<ServoUrl>,
+    pub is_editable: bool,
+    pub selected_text: Option<String>,
+}
+
+// The element at a point. `bounds` is (x, y, width, height) in logical
+// pixels, relative to the view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HitTestResult {
+    pub tag_name: String,
+    pub role: Option<String>,
+    pub link_url: Option<ServoUrl>,
+    pub bounds: (f32, f32, f32, f32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PermissionKind {
+    Geolocation,
+    Notifications,
+    Camera,
+    Microphone,
+}
+
+// Serializable for the IpcSender of PermissionRequest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PermissionDecision {
+    Grant,
+    Deny,
+    Prompt,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct FindOptions {
+    pub case_sensitive: bool,
+    pub wrap_around: bool,
+}
+
+#[derive(Debug)]
+pub enum LoadFailure {
+    UnknownBrowser,
+    Network(NetErrorKind, String),
+    // The browser was closed before the load finished.
+    Canceled,
+}
+
+// Resolves on the next LoadEnd or LoadError of the browser. Servo only
+// makes progress while the compositor is pumped with perform_updates.
+pub struct LoadFuture {
+    receiver: oneshot::Receiver<Result<(), LoadFailure>>,
+}
+
+impl Future for LoadFuture {
+    type Item = ();
+    type Error = LoadFailure;
+
+    fn poll(&mut self) -> Poll<(), LoadFailure> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(()))) => Ok(Async::Ready(())),
+            Ok(Async::Ready(Err(failure))) => Err(failure),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(LoadFailure::Canceled),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct HistoryEntry {
+    pub url: ServoUrl,
+    pub title: Option<String>,
+}
+
+// One browser of a saved session. `history` includes `url`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
+pub struct SessionEntry {
+    pub url: ServoUrl,
+    pub history: Vec<HistoryEntry>,
+}
+
+// Bounds the events waiting for get_events, for embedders that may stop
+// polling for a while. The default keeps every event.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct EventQueuePolicy {
+    // Past this, the oldest events that can be lost go first: cursor,
+    // status, hover, progress, scroll, network log and frame notifications.
+    // Load lifecycle events and the ones waiting for a reply are always
+    // kept.
+    pub max_events: Option<usize>,
+    // Only keep the latest of consecutive CursorChanged, and of
+    // consecutive StatusChanged or LinkHovered of the same browser.
+    pub coalesce: bool,
+}
+
+// Pops queued events one at a time, so the queue keeps its buffer.
+// Events queued while draining are returned too.
+pub struct DrainEvents<'a> {
+    queue: &'a RefCell<VecDeque<BrowserEvent>>,
+}
+
+impl<'a> Iterator for DrainEvents<'a> {
+    type Item = BrowserEvent;
+    fn next(&mut self) -> Option<BrowserEvent> {
+        self.queue.borrow_mut().pop_front()
+    }
+}
+
+// What run_until collected before giving up.
+#[derive(Debug)]
+pub struct Timeout {
+    pub events: Vec<BrowserEvent>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DownloadId(u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadInfo {
+    pub id: DownloadId,
+    pub url: ServoUrl,
+    pub suggested_filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub content_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthChallenge {
+    pub host: String,
+    pub realm: Option<String>,
+}
+
+// Replies go through an IpcSender, which only clones and sends
+// serializable types.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    // Why the certificate was rejected, as reported by the TLS stack.
+    pub error: String,
+}
+
+#[derive(Debug)]
+pub enum ServoError {
+    ResourcesPathMissing,
+    ResourcesPathNotUtf8,
+    BrowserCreationFailed,
+    GlContextUnavailable,
+    UnknownBrowser,
+    AlreadyStarted,
+    NoFrame,
+    Unsupported,
+    UnknownPref(String),
+    ForbiddenHeader(String),
+    InvalidUrl,
+    FontDirectoryMissing(PathBuf),
+    CompositorGone,
+    NotFocused,
+}
+
+impl fmt::Display for ServoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServoError::UnknownPref(ref key) => write!(f, "{}: {}", self.description(), key),
+            ServoError::ForbiddenHeader(ref name) => write!(f, "{}: {}", self.description(), name),
+            ServoError::FontDirectoryMissing(ref path) => write!(f, "{}: {}", self.description(), path.display()),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for ServoError {
+    fn description(&self) -> &str {
+        match *self {
+            ServoError::ResourcesPathMissing => "Can't find resources directory",
+            ServoError::ResourcesPathNotUtf8 => "Resources path is not valid UTF-8",
+            ServoError::BrowserCreationFailed => "Servo failed to create the browser",
+            ServoError::GlContextUnavailable => "Can't make the GL context current",
+            ServoError::UnknownBrowser => "Unknown browser id",
+            ServoError::AlreadyStarted => "Servo options can't be changed once Servo has started",
+            ServoError::NoFrame => "No frame has been composited yet",
+            ServoError::Unsupported => "Not supported by this version of Servo",
+            ServoError::UnknownPref(_) => "Unknown preference",
+            ServoError::ForbiddenHeader(_) => "Header can't be set by the embedder",
+            ServoError::InvalidUrl => "Can't build a valid URL",
+            ServoError::FontDirectoryMissing(_) => "Can't find font directory",
+            ServoError::CompositorGone => "The compositor was dropped",
+            ServoError::NotFocused => "The browser isn't the focused one",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct DrawableGeometry {
     pub view_size: (u32, u32),
     pub margins: (u32, u32, u32, u32),
@@ -58,95 +386,1654 @@ pub struct DrawableGeometry {
     pub hidpi_factor: f32,
 }
 
-pub trait GLMethods {
-    fn make_current(&self) -> Result<(),()>;
-    fn swap_buffers(&self);
-    fn get_gl(&self) -> Rc<gl::Gl>;
-}
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum JsError {
+    Exception(String),
+    UnsupportedType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+    pub secure: bool,
+    pub expiry: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DragData {
+    Text(String),
+    Url(ServoUrl),
+    Files(Vec<PathBuf>),
+}
+
+bitflags! {
+    pub flags StorageKinds: u8 {
+        const LOCAL_STORAGE = 0x01,
+        const SESSION_STORAGE = 0x02,
+        const INDEXED_DB = 0x04,
+        const CACHE_STORAGE = 0x08,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunAt {
+    // Before any script of the page.
+    DocumentStart,
+    // Once the DOM is parsed, before `load`.
+    DocumentEnd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+    NoPreference,
+}
+
+// Sizes are in bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MemoryReport {
+    pub resident: u64,
+    pub js_heap: u64,
+    pub layout: u64,
+    pub images: u64,
+    pub other: u64,
+}
+
+// Sizes are in millimeters.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PrintOptions {
+    pub page_size: (f32, f32),
+    pub margins: (f32, f32, f32, f32),
+}
+
+impl Default for PrintOptions {
+    fn default() -> PrintOptions {
+        PrintOptions {
+            page_size: (210.0, 297.0),
+            margins: (10.0, 10.0, 10.0, 10.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameImage {
+    pub width: u32,
+    pub height: u32,
+    // RGBA, top row first
+    pub pixels: Vec<u8>,
+}
+
+pub type FaviconImage = FrameImage;
+
+pub trait GLMethods {
+    fn make_current(&self) -> Result<(),()>;
+    fn swap_buffers(&self);
+    fn get_gl(&self) -> Rc<gl::Gl>;
+}
+
+// Serves Servo's resources (prefs.json, user agent stylesheets, …) by
+// file name, instead of a resources directory.
+pub trait ResourceProvider {
+    fn get_resource(&self, name: &str) -> Option<Cow<[u8]>>;
+}
+
+// Servo reads and writes the system clipboard itself, and only asks
+// whether it may: having a provider allows it. Copy and paste from web
+// content never call the provider. Only the compositor's own helpers,
+// like can_paste, do.
+pub trait ClipboardProvider {
+    fn get_text(&self) -> Option<String>;
+    fn set_text(&self, text: String);
+}
+
+// GL methods for an offscreen context. There is no window to swap, so
+// presenting only flushes the pending GL commands.
+struct HeadlessGL {
+    gl: Rc<gl::Gl>,
+}
+
+impl GLMethods for HeadlessGL {
+    fn make_current(&self) -> Result<(),()> {
+        Ok(())
+    }
+    fn swap_buffers(&self) {
+        self.gl.flush();
+    }
+    fn get_gl(&self) -> Rc<gl::Gl> {
+        self.gl.clone()
+    }
+}
+
+// Headless compositors are driven by perform_updates only.
+struct HeadlessWaker;
+
+impl EventLoopWaker for HeadlessWaker {
+    fn clone(&self) -> Box<EventLoopWaker + Send> {
+        box HeadlessWaker
+    }
+    fn wake(&self) {
+    }
+}
+
+pub struct Constellation {
+    setup_logging: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrefValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl From<PrefValue> for ServoPrefValue {
+    fn from(value: PrefValue) -> ServoPrefValue {
+        match value {
+            PrefValue::Bool(b) => ServoPrefValue::Boolean(b),
+            PrefValue::Int(i) => ServoPrefValue::Number(i as f64),
+            PrefValue::Float(f) => ServoPrefValue::Number(f),
+            PrefValue::String(s) => ServoPrefValue::String(s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    // Hosts reached directly, without the proxy.
+    pub bypass: Vec<String>,
+}
+
+pub struct ProtocolResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<Read + Send>,
+}
+
+pub type ProtocolHandler = Box<Fn(ServoUrl) -> ProtocolResponse + Send + Sync>;
+
+#[derive(Default)]
+pub struct ConstellationBuilder {
+    resources_path: Option<PathBuf>,
+    user_agent: Option<String>,
+    devtools_port: Option<u16>,
+    log_filter: Option<String>,
+    proxy: Option<ProxyConfig>,
+    protocols: HashMap<String, ProtocolHandler>,
+    prefs: Vec<(String, PrefValue)>,
+    font_directories: Vec<PathBuf>,
+    resource_provider: Option<Box<ResourceProvider + Send + Sync>>,
+    accept_languages: Option<Vec<String>>,
+}
+
+pub struct Compositor {
+    servo: Rc<RefCell<Servo<WindowCallback>>>,
+    callbacks: Rc<WindowCallback>,
+    browsers: RefCell<Vec<BrowserId>>,
+    zoom: Cell<f32>,
+    pinch_zoom: Cell<f32>,
+    mouse_down_point: Cell<Option<TypedPoint2D<f32, DevicePixel>>>,
+    touches: RefCell<HashSet<u32>>,
+    resize_debounce: Cell<Option<Duration>>,
+    reserved_shortcuts: RefCell<Vec<(Key, KeyModifiers)>>,
+    command_sender: Sender<HandleCommand>,
+    command_receiver: Receiver<HandleCommand>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ViewId(u32);
+
+enum HandleCommand {
+    LoadUrl(BrowserId, ServoUrl),
+    Reload(BrowserId),
+    Resize(DrawableGeometry),
+}
+
+// Drives a compositor from another thread. Commands are queued and the
+// compositor's EventLoopWaker is called: they run on the next
+// perform_updates, on the compositor's thread. Failures, like an unknown
+// browser, are only logged there.
+pub struct CompositorHandle {
+    sender: Sender<HandleCommand>,
+    waker: Box<EventLoopWaker + Send>,
+}
+
+impl Clone for CompositorHandle {
+    fn clone(&self) -> CompositorHandle {
+        CompositorHandle {
+            sender: self.sender.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+impl CompositorHandle {
+    pub fn load_url(&self, id: BrowserId, url: ServoUrl) -> Result<(), ServoError> {
+        self.send(HandleCommand::LoadUrl(id, url))
+    }
+    pub fn reload(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.send(HandleCommand::Reload(id))
+    }
+    pub fn resize(&self, geometry: DrawableGeometry) -> Result<(), ServoError> {
+        self.send(HandleCommand::Resize(geometry))
+    }
+
+    fn send(&self, command: HandleCommand) -> Result<(), ServoError> {
+        self.sender.send(command).map_err(|_| ServoError::CompositorGone)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+// Servo renders into a single region, the one of the view that was shown
+// last. Other views keep their geometry until they are shown.
+pub struct View {
+    id: ViewId,
+    servo: Rc<RefCell<Servo<WindowCallback>>>,
+    callbacks: Rc<WindowCallback>,
+}
+
+impl View {
+    pub fn id(&self) -> ViewId {
+        self.id
+    }
+    pub fn geometry(&self) -> DrawableGeometry {
+        self.callbacks.views.borrow()[&self.id]
+    }
+    pub fn window_rect(&self) -> TypedRect<u32, DevicePixel> {
+        window_rect_for(&self.geometry())
+    }
+    // Replaces a debounced resize of this view that is still pending.
+    pub fn resize(&self, geometry: DrawableGeometry) {
+        self.callbacks.views.borrow_mut().insert(self.id, geometry);
+        if let Some((Some(view), _, _)) = self.callbacks.pending_resize.get() {
+            if view == self.id {
+                self.callbacks.pending_resize.set(None);
+            }
+        }
+        if self.callbacks.active_view.get() == Some(self.id) {
+            if let Some(event) = update_geometry(&self.callbacks, geometry) {
+                send_to_servo(&self.servo, &self.callbacks, vec![event]);
+            }
+        }
+    }
+    pub fn show(&self, id: Option<BrowserId>) -> Result<(), ServoError> {
+        let select = select_browser(&self.callbacks, id)?;
+        // The view's latest geometry is applied below, and a pending
+        // resize of another view doesn't matter anymore.
+        self.callbacks.pending_resize.set(None);
+        self.callbacks.active_view.set(Some(self.id));
+        let mut events: Vec<_> = update_geometry(&self.callbacks, self.geometry()).into_iter().collect();
+        events.push(select);
+        send_to_servo(&self.servo, &self.callbacks, events);
+        Ok(())
+    }
+}
+
+// Views don't own GL resources: Servo's layers belong to its browsers.
+// Dropping the active view leaves Servo with its geometry until another
+// view is shown or the compositor is resized.
+impl Drop for View {
+    fn drop(&mut self) {
+        self.callbacks.views.borrow_mut().remove(&self.id);
+        if self.callbacks.active_view.get() == Some(self.id) {
+            self.callbacks.active_view.set(None);
+        }
+    }
+}
+
+#[derive(Default)]
+struct BrowserState {
+    history: Vec<HistoryEntry>,
+    current: usize,
+    title: Option<String>,
+    title_override: Option<String>,
+    load_started: Option<Instant>,
+    load_progress: f32,
+    muted: bool,
+    hidden: bool,
+    load_waiters: Vec<oneshot::Sender<Result<(), LoadFailure>>>,
+}
+
+struct WindowCallback {
+    gl_methods: RefCell<Rc<GLMethods>>,
+    gl_context_lost: Cell<bool>,
+    waker: Box<EventLoopWaker + 'static + Send>,
+    clipboard: Option<Box<ClipboardProvider>>,
+    event_queue: RefCell<VecDeque<BrowserEvent>>,
+    event_queue_policy: EventQueuePolicy,
+    browser_states: RefCell<HashMap<BrowserId, BrowserState>>,
+    selected_browser: Cell<Option<BrowserId>>,
+    frame_presented: Cell<bool>,
+    suspended: Cell<bool>,
+    cursor: Cell<Option<Cursor>>,
+    background_color: Cell<[f32; 4]>,
+    views: RefCell<HashMap<ViewId, DrawableGeometry>>,
+    active_view: Cell<Option<ViewId>>,
+    next_view_id: Cell<u32>,
+    event_handler: RefCell<Option<Box<FnMut(BrowserEvent)>>>,
+    // A debounced resize of the given view, waiting for perform_updates.
+    pending_resize: Cell<Option<(Option<ViewId>, DrawableGeometry, Instant)>>,
+    // Geometry of the active view, the one Servo renders with.
+    pub geometry: Cell<DrawableGeometry>,
+}
+
+impl ConstellationBuilder {
+    pub fn new() -> ConstellationBuilder {
+        ConstellationBuilder::default()
+    }
+
+    pub fn resources_path(mut self, path: PathBuf) -> ConstellationBuilder {
+        self.resources_path = Some(path);
+        self
+    }
+
+    // Replaces resources_path. Servo reads its resources from disk, so
+    // `build` fails with `ServoError::Unsupported` when a provider is set.
+    pub fn resource_provider(mut self, provider: Box<ResourceProvider + Send + Sync>) -> ConstellationBuilder {
+        self.resource_provider = Some(provider);
+        self
+    }
+
+    // Only has an effect before the first compositor is created. `build`
+    // returns `ServoError::AlreadyStarted` otherwise.
+    pub fn user_agent(mut self, user_agent: String) -> ConstellationBuilder {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    // Needs a Servo built with devtools support. Like the user agent, it
+    // must be set before the first compositor is created. Servo doesn't
+    // say when the server listens, so DevtoolsServerStarted isn't sent.
+    pub fn enable_devtools(mut self, port: u16) -> ConstellationBuilder {
+        self.devtools_port = Some(port);
+        self
+    }
+
+    // Servo's logger is only installed when a filter is given, otherwise
+    // embedders are free to set up their own `log` backend. The filter uses
+    // the RUST_LOG syntax.
+    pub fn log_filter(mut self, filter: &str) -> ConstellationBuilder {
+        self.log_filter = Some(filter.to_owned());
+        self
+    }
+
+    // The network threads start with the first compositor, `build` fails
+    // with `ServoError::AlreadyStarted` after that. Servo's HTTP stack
+    // can't go through a proxy yet, so `build` otherwise fails with
+    // `ServoError::Unsupported`.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> ConstellationBuilder {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // BCP 47 tags ("fr-CH", "fr", "en"), most preferred first. Used for
+    // Accept-Language and navigator.languages. Servo hardcodes both to
+    // en-US for now: `build` fails with `ServoError::Unsupported`, or
+    // `ServoError::AlreadyStarted` like for the proxy.
+    pub fn accept_languages(mut self, languages: Vec<String>) -> ConstellationBuilder {
+        self.accept_languages = Some(languages);
+        self
+    }
+
+    // Requests to `scheme` go to `handler` instead of the network. Servo's
+    // fetch only knows its built-in schemes for now, so `build` fails with
+    // `ServoError::Unsupported` when a protocol is registered.
+    pub fn register_protocol(mut self, scheme: &str, handler: ProtocolHandler) -> ConstellationBuilder {
+        self.protocols.insert(scheme.to_lowercase(), handler);
+        self
+    }
+
+    // `build` fails with `ServoError::FontDirectoryMissing` if `path` isn't
+    // a directory. Servo's font backends only look at system fonts for
+    // now, so it otherwise fails with `ServoError::Unsupported`.
+    pub fn add_font_directory(mut self, path: PathBuf) -> ConstellationBuilder {
+        self.font_directories.push(path);
+        self
+    }
+
+    pub fn set_pref(mut self, key: &str, value: PrefValue) -> ConstellationBuilder {
+        self.prefs.push((key.to_owned(), value));
+        self
+    }
+
+    pub fn set_prefs(mut self, prefs: HashMap<String, PrefValue>) -> ConstellationBuilder {
+        self.prefs.extend(prefs);
+        self
+    }
+
+    pub fn build(self) -> Result<Constellation, ServoError> {
+        if self.proxy.is_some() || self.accept_languages.is_some() {
+            if OPTS_FROZEN.load(Ordering::SeqCst) {
+                return Err(ServoError::AlreadyStarted);
+            }
+            return Err(ServoError::Unsupported);
+        }
+        if !self.protocols.is_empty() {
+            return Err(ServoError::Unsupported);
+        }
+        if let Some(path) = self.font_directories.iter().find(|path| !path.is_dir()) {
+            return Err(ServoError::FontDirectoryMissing(path.clone()));
+        }
+        if !self.font_directories.is_empty() {
+            return Err(ServoError::Unsupported);
+        }
+        if self.resource_provider.is_some() {
+            return Err(ServoError::Unsupported);
+        }
+
+        let path = match self.resources_path {
+            Some(path) => path,
+            None => {
+                env::current_dir()
+                    .map_err(|_| ServoError::ResourcesPathMissing)?
+                    .join("servo_resources/")
+            }
+        };
+        if !path.exists() {
+            return Err(ServoError::ResourcesPathMissing);
+        }
+        if !self.prefs.is_empty() {
+            // Known keys are the ones of the default prefs, in the
+            // resources directory. PREFS itself isn't touched until every
+            // check passed.
+            let defaults = File::open(path.join("prefs.json"))
+                .ok()
+                .and_then(|file| read_prefs_from_file(file).ok())
+                .ok_or(ServoError::ResourcesPathMissing)?;
+            for &(ref key, _) in &self.prefs {
+                if !defaults.contains_key(key) {
+                    return Err(ServoError::UnknownPref(key.clone()));
+                }
+            }
+        }
+        let path = path.to_str().ok_or(ServoError::ResourcesPathNotUtf8)?.to_string();
+        let set_opts = self.user_agent.is_some() || self.devtools_port.is_some();
+        if set_opts && OPTS_FROZEN.load(Ordering::SeqCst) {
+            return Err(ServoError::AlreadyStarted);
+        }
+
+        // Nothing can fail past this point.
+        set_resources_path(Some(path));
+        for (key, value) in self.prefs {
+            PREFS.set(&key, value.into());
+        }
+        if set_opts {
+            let mut opts = opts::default_opts();
+            if let Some(user_agent) = self.user_agent {
+                opts.user_agent = user_agent.into();
+            }
+            opts.devtools_port = self.devtools_port;
+            opts::set_defaults(opts);
+        }
+
+        if let Some(ref filter) = self.log_filter {
+            env::set_var("RUST_LOG", filter);
+        }
+
+        Ok(Constellation {
+            setup_logging: self.log_filter.is_some(),
+        })
+    }
+}
+
+impl Constellation {
+    pub fn new() -> Result<Constellation, ServoError> {
+        ConstellationBuilder::new().build()
+    }
+
+    pub fn new_compositor(&self, gl_methods: Rc<GLMethods>, waker: Box<EventLoopWaker + Send>, geometry: DrawableGeometry, clipboard: Option<Box<ClipboardProvider>>, event_queue_policy: EventQueuePolicy) -> Compositor {
+        OPTS_FROZEN.store(true, Ordering::SeqCst);
+        let cb = Rc::new(WindowCallback::new(gl_methods, waker, geometry, clipboard, event_queue_policy));
+        let (command_sender, command_receiver) = mpsc::channel();
+        let servo = Servo::new(cb.clone());
+        if self.setup_logging {
+            servo.setup_logging();
+        }
+        Compositor {
+            servo: Rc::new(RefCell::new(servo)),
+            callbacks: cb.clone(),
+            browsers: RefCell::new(Vec::new()),
+            zoom: Cell::new(1.0),
+            pinch_zoom: Cell::new(1.0),
+            mouse_down_point: Cell::new(None),
+            touches: RefCell::new(HashSet::new()),
+            resize_debounce: Cell::new(None),
+            reserved_shortcuts: RefCell::new(Vec::new()),
+            command_sender: command_sender,
+            command_receiver: command_receiver,
+        }
+    }
+
+    // Bring your own offscreen context: `gl` must come from one (OSMesa,
+    // pbuffer) that is already current on this thread, with a framebuffer
+    // of `size` times `hidpi_factor` bound. No framebuffer is created here,
+    // make_current does nothing and present doesn't swap. Read frames back
+    // with capture_frame.
+    pub fn new_offscreen_compositor(&self, gl: Rc<gl::Gl>, size: (u32, u32), hidpi_factor: f32) -> Compositor {
+        let geometry = DrawableGeometry {
+            view_size: size,
+            margins: (0, 0, 0, 0),
+            position: (0, 0),
+            hidpi_factor: hidpi_factor,
+        };
+        self.new_compositor(Rc::new(HeadlessGL { gl: gl }), box HeadlessWaker, geometry, None, EventQueuePolicy::default())
+    }
+
+    pub fn new_browser(&self, url: ServoUrl, compositor: &Compositor /*temporary*/) -> Result<BrowserId, ServoError> {
+        compositor.new_browser(url)
+    }
+}
+
+impl Compositor {
+    pub fn new_view(&self, geometry: DrawableGeometry) -> View {
+        let id = ViewId(self.callbacks.next_view_id.get());
+        self.callbacks.next_view_id.set(id.0 + 1);
+        self.callbacks.views.borrow_mut().insert(id, geometry);
+        if self.callbacks.active_view.get().is_none() {
+            self.callbacks.active_view.set(Some(id));
+            if let Some(event) = update_geometry(&self.callbacks, geometry) {
+                self.send_events(vec![event]);
+            }
+        }
+        View {
+            id: id,
+            servo: self.servo.clone(),
+            callbacks: self.callbacks.clone(),
+        }
+    }
+    pub fn view_count(&self) -> usize {
+        self.callbacks.views.borrow().len()
+    }
+    // Resizes the active view. With a debounce interval, Servo only
+    // reflows once no resize came for that long, on perform_updates.
+    pub fn resize(&self, geometry: DrawableGeometry) {
+        let view = self.callbacks.active_view.get();
+        if let Some(view) = view {
+            self.callbacks.views.borrow_mut().insert(view, geometry);
+        }
+        if self.resize_debounce.get().is_some() {
+            self.callbacks.pending_resize.set(Some((view, geometry, Instant::now())));
+            return;
+        }
+        if let Some(event) = update_geometry(&self.callbacks, geometry) {
+            self.send_events(vec![event]);
+        }
+    }
+    // None, the default, applies every resize right away.
+    pub fn set_resize_debounce(&self, interval: Option<Duration>) {
+        self.resize_debounce.set(interval);
+        if interval.is_none() {
+            self.apply_pending_resize(true);
+        }
+    }
+    // For a window moving to a screen with a different density. The
+    // factor is the window's, so every view gets it. Not debounced: the
+    // layout is redone at the new scale right away.
+    pub fn set_hidpi_factor(&self, factor: f32) {
+        for geometry in self.callbacks.views.borrow_mut().values_mut() {
+            geometry.hidpi_factor = factor;
+        }
+        let mut geometry = match self.callbacks.pending_resize.get() {
+            Some((_, geometry, _)) => geometry,
+            None => self.callbacks.geometry.get(),
+        };
+        if geometry.hidpi_factor == factor {
+            return;
+        }
+        self.callbacks.pending_resize.set(None);
+        geometry.hidpi_factor = factor;
+        if let Some(view) = self.callbacks.active_view.get() {
+            self.callbacks.views.borrow_mut().insert(view, geometry);
+        }
+        let mut events: Vec<_> = update_geometry(&self.callbacks, geometry).into_iter().collect();
+        events.push(WindowEvent::Refresh);
+        self.send_events(events);
+    }
+    pub fn set_margins(&self, top: u32, right: u32, bottom: u32, left: u32) {
+        let mut geometry = match self.callbacks.pending_resize.get() {
+            Some((_, geometry, _)) => geometry,
+            None => self.callbacks.geometry.get(),
+        };
+        let (width, height) = geometry.view_size;
+        let top = top.min(height);
+        let bottom = bottom.min(height - top);
+        let left = left.min(width);
+        let right = right.min(width - left);
+        geometry.margins = (top, right, bottom, left);
+        self.resize(geometry);
+    }
+    pub fn new_browser(&self, url: ServoUrl) -> Result<BrowserId, ServoError> {
+        info!("Creating browser for {}", url);
+        let id = self.servo
+            .borrow()
+            .create_browser(url)
+            .map_err(|_| {
+                warn!("Servo failed to create a browser");
+                ServoError::BrowserCreationFailed
+            })?;
+        debug!("Created browser {:?}", id);
+        self.browsers.borrow_mut().push(id);
+        self.callbacks.browser_states.borrow_mut().insert(id, BrowserState::default());
+        // Servo paints nothing until a browser is selected, so the first
+        // one is shown right away. Later ones wait for show.
+        if self.callbacks.selected_browser.get().is_none() {
+            self.show(Some(id))?;
+        }
+        Ok(id)
+    }
+    // Would get its own cookie jar and storage, dropped on close. Servo
+    // has one resource thread for every browser and can't isolate them
+    // yet, so this fails with `ServoError::Unsupported`.
+    pub fn new_private_browser(&self, _url: ServoUrl) -> Result<BrowserId, ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    // Servo creates the browser synchronously and a blocked constellation
+    // can't be interrupted from here, so no deadline can be honoured and
+    // this fails with `ServoError::Unsupported`.
+    pub fn new_browser_with_timeout(&self, _url: ServoUrl, _timeout: Duration) -> Result<BrowserId, ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    // One browser per entry, in order. Entries Servo fails to create a
+    // browser for are skipped. Servo's session history can't be seeded
+    // from the embedder, so only `url` is loaded and `history` is ignored.
+    pub fn restore_session(&self, entries: Vec<SessionEntry>) -> Vec<BrowserId> {
+        entries.into_iter().filter_map(|entry| self.new_browser(entry.url).ok()).collect()
+    }
+    // Browsers that haven't committed a first navigation yet have no URL
+    // and are left out.
+    pub fn export_session(&self) -> Vec<SessionEntry> {
+        let states = self.callbacks.browser_states.borrow();
+        self.browsers.borrow().iter().filter_map(|id| {
+            let state = match states.get(id) {
+                Some(state) => state,
+                None => return None,
+            };
+            state.history.get(state.current).map(|entry| {
+                SessionEntry {
+                    url: entry.url.clone(),
+                    history: state.history.clone(),
+                }
+            })
+        }).collect()
+    }
+    pub fn browsers(&self) -> Vec<BrowserId> {
+        self.browsers.borrow().clone()
+    }
+    pub fn close_browser(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        info!("Closing browser {:?}", id);
+        self.handle_event(WindowEvent::CloseBrowser(id));
+        self.browsers.borrow_mut().retain(|&b| b != id);
+        self.callbacks.browser_states.borrow_mut().remove(&id);
+        if self.callbacks.selected_browser.get() == Some(id) {
+            self.callbacks.selected_browser.set(None);
+        }
+        Ok(())
+    }
+    pub fn load_url(&self, id: BrowserId, url: ServoUrl) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        info!("Loading {} in {:?}", url, id);
+        self.handle_event(WindowEvent::LoadUrl(id, url));
+        Ok(())
+    }
+    // Data URLs have no base, so relative URLs in `html` resolve against
+    // `base_url` through a <base> element.
+    pub fn load_html(&self, id: BrowserId, html: &str, base_url: Option<ServoUrl>) -> Result<(), ServoError> {
+        let mut url = String::from("data:text/html;charset=utf-8,");
+        match base_url {
+            Some(base_url) => {
+                let base = format!("<base href=\"{}\">", base_url.as_str().replace('"', "%22"));
+                let (before, after) = html.split_at(base_insertion_point(html));
+                percent_encode(before, &mut url);
+                percent_encode(&base, &mut url);
+                percent_encode(after, &mut url);
+            }
+            None => percent_encode(html, &mut url),
+        }
+        let url = ServoUrl::parse(&url).map_err(|_| ServoError::InvalidUrl)?;
+        self.load_url(id, url)
+    }
+    pub fn load_file(&self, id: BrowserId, path: &Path) -> Result<(), ServoError> {
+        let path = path.canonicalize().map_err(|_| ServoError::InvalidUrl)?;
+        let url = ServoUrl::from_file_path(path).map_err(|_| ServoError::InvalidUrl)?;
+        self.load_url(id, url)
+    }
+    // Forbidden headers (Host, Cookie, Proxy-*, Sec-*…) are rejected. Servo
+    // can't take headers for a top-level load yet, so valid requests fail
+    // with `ServoError::Unsupported`.
+    pub fn load_url_with_headers(&self, id: BrowserId, _url: ServoUrl, headers: Vec<(String, String)>) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        for &(ref name, _) in &headers {
+            let lower = name.to_lowercase();
+            if FORBIDDEN_HEADERS.contains(&&*lower) || lower.starts_with("proxy-") || lower.starts_with("sec-") {
+                return Err(ServoError::ForbiddenHeader(name.clone()));
+            }
+        }
+        Err(ServoError::Unsupported)
+    }
+    pub fn load_url_async(&self, id: BrowserId, url: ServoUrl) -> LoadFuture {
+        let (sender, receiver) = oneshot::channel();
+        if self.check_browser(id).is_err() {
+            let _ = sender.send(Err(LoadFailure::UnknownBrowser));
+        } else {
+            if let Some(state) = self.callbacks.browser_states.borrow_mut().get_mut(&id) {
+                // Drop the waiters of futures that have been dropped.
+                state.load_waiters.retain(|waiter| !waiter.is_canceled());
+                state.load_waiters.push(sender);
+            }
+            self.handle_event(WindowEvent::LoadUrl(id, url));
+        }
+        LoadFuture { receiver: receiver }
+    }
+    // Servo only has one reload mode, which revalidates cached resources.
+    // `bypass_cache` is accepted so embedders don't have to change their
+    // call sites once a hard reload is available.
+    pub fn reload(&self, id: BrowserId, _bypass_cache: bool) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        self.handle_event(WindowEvent::Reload(id));
+        Ok(())
+    }
+    pub fn stop_loading(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        self.handle_event(WindowEvent::Stop(id));
+        Ok(())
+    }
+    pub fn go_back(&self, id: BrowserId, steps: usize) -> bool {
+        let available = match self.callbacks.browser_states.borrow().get(&id) {
+            Some(state) => state.current,
+            None => 0,
+        };
+        self.navigate(id, WindowNavigateMsg::Back, steps.min(available))
+    }
+    pub fn go_forward(&self, id: BrowserId, steps: usize) -> bool {
+        let available = match self.callbacks.browser_states.borrow().get(&id) {
+            Some(state) => state.history.len().saturating_sub(state.current + 1),
+            None => 0,
+        };
+        self.navigate(id, WindowNavigateMsg::Forward, steps.min(available))
+    }
+    pub fn browser_url(&self, id: BrowserId) -> Option<ServoUrl> {
+        self.callbacks.browser_states.borrow().get(&id).and_then(|state| {
+            state.history.get(state.current).map(|entry| entry.url.clone())
+        })
+    }
+    // The override if there is one, the page title otherwise.
+    pub fn browser_title(&self, id: BrowserId) -> Option<String> {
+        self.callbacks.browser_states.borrow().get(&id).and_then(|state| {
+            state.title_override.clone().or_else(|| state.title.clone())
+        })
+    }
+    // While an override is set, the page title is still tracked, for
+    // history, but TitleChanged isn't sent for this browser.
+    pub fn set_title_override(&self, id: BrowserId, title: Option<String>) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        if let Some(state) = self.callbacks.browser_states.borrow_mut().get_mut(&id) {
+            state.title_override = title;
+        }
+        Ok(())
+    }
+    // Between LoadStart and LoadEnd or LoadError. Servo only reports loads
+    // of the top-level document, frames loading later don't count.
+    pub fn is_loading(&self, id: BrowserId) -> bool {
+        self.callbacks.browser_states.borrow().get(&id).map_or(false, |state| state.load_started.is_some())
+    }
+    // Servo doesn't report scroll offsets to embedders yet, so this fails
+    // with `ServoError::Unsupported`.
+    pub fn scroll_position(&self, id: BrowserId) -> Result<(f32, f32), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+    pub fn history(&self, id: BrowserId) -> Option<(Vec<HistoryEntry>, usize)> {
+        self.callbacks.browser_states.borrow().get(&id).and_then(|state| {
+            if state.history.is_empty() {
+                None
+            } else {
+                Some((state.history.clone(), state.current))
+            }
+        })
+    }
+    pub fn show(&self, id: Option<BrowserId>) -> Result<(), ServoError> {
+        let event = select_browser(&self.callbacks, id)?;
+        self.send_events(vec![event]);
+        Ok(())
+    }
+    pub fn focused_browser(&self) -> Option<BrowserId> {
+        self.callbacks.selected_browser.get()
+    }
+    // Would blur the active browser when the native window loses focus.
+    // Servo assumes its window is always focused and has no event for it
+    // yet, so this fails with `ServoError::Unsupported`.
+    pub fn set_window_focused(&self, _focused: bool) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    pub fn set_page_zoom(&self, factor: f32) {
+        let factor = factor.max(MIN_ZOOM).min(MAX_ZOOM);
+        let current = self.zoom.get();
+        if factor == current {
+            return;
+        }
+        // Servo's zoom event is relative to the current zoom.
+        self.handle_event(WindowEvent::Zoom(factor / current));
+        self.zoom.set(factor);
+    }
+    pub fn zoom_in(&self) {
+        let zoom = self.zoom.get() * ZOOM_STEP;
+        self.set_page_zoom(zoom);
+    }
+    pub fn zoom_out(&self) {
+        let zoom = self.zoom.get() / ZOOM_STEP;
+        self.set_page_zoom(zoom);
+    }
+    pub fn reset_zoom(&self) {
+        self.handle_event(WindowEvent::ResetZoom);
+        self.zoom.set(1.0);
+    }
+    pub fn current_zoom(&self) -> f32 {
+        self.zoom.get()
+    }
+    // Would scale fonts only, leaving images and the rest of the layout
+    // alone. Servo only has full page zoom, so this fails with
+    // `ServoError::Unsupported` and text_zoom stays at 1.0.
+    pub fn set_text_zoom(&self, _factor: f32) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn text_zoom(&self) -> f32 {
+        1.0
+    }
+    // Servo doesn't support a pinch center yet and always zooms around
+    // the viewport origin.
+    pub fn pinch_zoom(&self, magnification: f32, _center: (f32, f32)) {
+        let current = self.pinch_zoom.get();
+        let zoom = (current * magnification).max(MIN_PINCH_ZOOM).min(MAX_PINCH_ZOOM);
+        if zoom == current {
+            return;
+        }
+        self.handle_event(WindowEvent::PinchZoom(zoom / current));
+        self.pinch_zoom.set(zoom);
+    }
+    pub fn reset_pinch_zoom(&self) {
+        let current = self.pinch_zoom.get();
+        if current != 1.0 {
+            self.handle_event(WindowEvent::PinchZoom(1.0 / current));
+            self.pinch_zoom.set(1.0);
+        }
+    }
+
+    pub fn mouse_move(&self, x: f32, y: f32) {
+        let point = self.device_point(x, y);
+        self.handle_event(WindowEvent::MouseWindowMoveEventClass(point));
+    }
+    pub fn mouse_down(&self, button: MouseButton, x: f32, y: f32) {
+        let point = self.device_point(x, y);
+        self.mouse_down_point.set(Some(point));
+        self.handle_event(WindowEvent::MouseWindowEventClass(MouseWindowEvent::MouseDown(button, point)));
+    }
+    // Like a native mouse, a release at the press location is also a click.
+    pub fn mouse_up(&self, button: MouseButton, x: f32, y: f32) {
+        let point = self.device_point(x, y);
+        let mut events = vec![WindowEvent::MouseWindowEventClass(MouseWindowEvent::MouseUp(button, point))];
+        if self.mouse_down_point.get() == Some(point) {
+            events.push(WindowEvent::MouseWindowEventClass(MouseWindowEvent::Click(button, point)));
+        }
+        self.mouse_down_point.set(None);
+        self.send_events(events);
+    }
+
+    // Moves, releases and cancels of touch points that aren't down are
+    // dropped.
+    pub fn touch_down(&self, id: u32, x: f32, y: f32) {
+        if self.touches.borrow_mut().insert(id) {
+            self.send_touch(TouchEventType::Down, id, x, y);
+        }
+    }
+    pub fn touch_move(&self, id: u32, x: f32, y: f32) {
+        if self.touches.borrow().contains(&id) {
+            self.send_touch(TouchEventType::Move, id, x, y);
+        }
+    }
+    pub fn touch_up(&self, id: u32, x: f32, y: f32) {
+        if self.touches.borrow_mut().remove(&id) {
+            self.send_touch(TouchEventType::Up, id, x, y);
+        }
+    }
+    pub fn touch_cancel(&self, id: u32, x: f32, y: f32) {
+        if self.touches.borrow_mut().remove(&id) {
+            self.send_touch(TouchEventType::Cancel, id, x, y);
+        }
+    }
+    // A drag from outside the view, in logical coordinates like the mouse
+    // events. Servo doesn't implement drag and drop yet, so these fail
+    // with `ServoError::Unsupported`.
+    pub fn drag_enter(&self, _data: DragData, _x: f32, _y: f32) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn drag_over(&self, _x: f32, _y: f32) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn drop(&self, _x: f32, _y: f32) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn drag_leave(&self) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn scroll(&self, delta: (f32, f32), cursor: (f32, f32), phase: TouchEventType) {
+        let location = ScrollLocation::Delta(TypedPoint2D::new(delta.0, delta.1));
+        let cursor = self.device_point(cursor.0, cursor.1);
+        let cursor = TypedPoint2D::new(cursor.x as i32, cursor.y as i32);
+        self.handle_event(WindowEvent::Scroll(location, cursor, phase));
+    }
+    // Servo can only scroll to the edges of the page. Only the origin can
+    // be reached, other offsets fail with `ServoError::Unsupported`.
+    pub fn scroll_to(&self, absolute: (f32, f32)) -> Result<(), ServoError> {
+        if absolute != (0.0, 0.0) {
+            return Err(ServoError::Unsupported);
+        }
+        self.scroll_to_location(ScrollLocation::Start);
+        Ok(())
+    }
+    // Scroll events go to the focused browser. Other browsers can't be
+    // scrolled and fail with `ServoError::NotFocused`.
+    pub fn scroll_to_top(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_focused(id)?;
+        self.scroll_to_location(ScrollLocation::Start);
+        Ok(())
+    }
+    pub fn scroll_to_bottom(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_focused(id)?;
+        self.scroll_to_location(ScrollLocation::End);
+        Ok(())
+    }
+    // A same-document navigation: Servo scrolls to the anchor without
+    // reloading, and the fragment shows up in the history.
+    pub fn scroll_to_fragment(&self, id: BrowserId, fragment: &str) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        let mut url = self.browser_url(id).ok_or(ServoError::InvalidUrl)?.into_url();
+        url.set_fragment(Some(fragment.trim_left_matches('#')));
+        self.load_url(id, ServoUrl::from_url(url))
+    }
+
+    pub fn send_key(&self, ch: Option<char>, key: Key, state: KeyState, mods: KeyModifiers) {
+        self.handle_event(WindowEvent::KeyEvent(ch, key, state, mods));
+    }
+    // Composition goes to the focused field. Servo only takes key events
+    // that map to a physical key and has no composition events yet, so
+    // these fail with `ServoError::Unsupported`.
+    pub fn ime_set_composition(&self, _text: &str, _selection: Range<usize>) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn ime_commit(&self, _text: &str) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    // Shortcuts that never reach content. Pressing one only produces a
+    // BrowserEvent::Key, whether it comes from send_key or handle_event.
+    pub fn set_reserved_shortcuts(&self, shortcuts: Vec<(Key, KeyModifiers)>) {
+        *self.reserved_shortcuts.borrow_mut() = shortcuts;
+    }
+    // Characters that don't map to a key on a US keyboard are skipped.
+    pub fn type_text(&self, text: &str) {
+        let mut events = vec![];
+        for ch in text.chars() {
+            if let Some((key, mods)) = key_for_char(ch) {
+                events.push(WindowEvent::KeyEvent(Some(ch), key, KeyState::Pressed, mods));
+                events.push(WindowEvent::KeyEvent(None, key, KeyState::Released, mods));
+            }
+        }
+        self.send_events(events);
+    }
+
+    // The receiver resolves once the script has run. Servo doesn't expose
+    // script evaluation to embedders yet, so for now this always fails
+    // with `ServoError::Unsupported`.
+    pub fn evaluate_js(&self, id: BrowserId, _script: String) -> Result<Receiver<Result<JsValue, JsError>>, ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // Would run `source` in the page on every navigation of `id`. Servo
+    // only has its global userscripts option, read at startup, so this
+    // fails with `ServoError::Unsupported`.
+    pub fn add_content_script(&self, id: BrowserId, _source: String, _run_at: RunAt) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // The receiver gets None when nothing is at that point. Servo's hit
+    // testing only routes input events and can't describe the node it
+    // found yet, so this fails with `ServoError::Unsupported`.
+    pub fn hit_test(&self, id: BrowserId, _x: f32, _y: f32) -> Result<Receiver<Option<HitTestResult>>, ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // Servo doesn't hand downloads to the embedder yet: DownloadRequested
+    // is never sent, and these fail with `ServoError::Unsupported`.
+    pub fn accept_download(&self, _download: DownloadId, _path: PathBuf) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn cancel_download(&self, _download: DownloadId) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    // Cookies live on the resource thread, which is shared by every browser
+    // of this compositor. Servo doesn't give embedders access to it yet.
+    pub fn get_cookies(&self, _url: ServoUrl) -> Result<Vec<Cookie>, ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn clear_cookies(&self, _domain: Option<String>) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    // Storage threads are shared by every browser, and like the resource
+    // thread they aren't reachable from here yet.
+    pub fn clear_storage_for_origin(&self, _origin: &str, _kinds: StorageKinds) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    // HTTP and image caches are shared too: clearing them affects every
+    // browser. Like cookies, they aren't reachable from here yet.
+    pub fn clear_cache(&self) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+    pub fn clear_cache_for_origin(&self, _origin: &str) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    // The receiver fires once the file is fully written. Servo has no PDF
+    // backend yet, so this always fails with `ServoError::Unsupported`.
+    pub fn print_to_pdf(&self, id: BrowserId, _path: PathBuf, _options: Option<PrintOptions>) -> Result<Receiver<Result<(), ServoError>>, ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // Matches are reported through BrowserEvent::FindResult. Servo can't
+    // search pages yet, so these fail with `ServoError::Unsupported`.
+    pub fn find_in_page(&self, id: BrowserId, _query: String, _options: FindOptions) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+    pub fn find_next(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+    pub fn find_previous(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+    pub fn stop_finding(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // Servo doesn't output audio yet, so this only records the state for
+    // is_muted. Muting doesn't change autoplay: media in a muted browser
+    // still plays, silently.
+    pub fn set_muted(&self, id: BrowserId, muted: bool) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        if let Some(state) = self.callbacks.browser_states.borrow_mut().get_mut(&id) {
+            state.muted = muted;
+        }
+        Ok(())
+    }
+    pub fn is_muted(&self, id: BrowserId) -> bool {
+        self.callbacks.browser_states.borrow().get(&id).map_or(false, |state| state.muted)
+    }
+
+    // A hidden browser isn't composited, even when selected, so it
+    // doesn't produce NewFrameReady events. Servo doesn't let embedders
+    // throttle timers and animation frames yet, so these keep running.
+    pub fn set_browser_visible(&self, id: BrowserId, visible: bool) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        if let Some(state) = self.callbacks.browser_states.borrow_mut().get_mut(&id) {
+            state.hidden = !visible;
+        }
+        Ok(())
+    }
+
+    // Servo only reports network activity to the devtools, not to
+    // embedders, so this fails with `ServoError::Unsupported`.
+    pub fn set_network_logging(&self, id: BrowserId, _enabled: bool) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+
+    // With the hidpi factor of the active view. Points aren't clamped.
+    pub fn logical_to_device(&self, point: (f32, f32)) -> (f32, f32) {
+        self.callbacks.logical_to_device(point)
+    }
+    pub fn device_to_logical(&self, point: (f32, f32)) -> (f32, f32) {
+        self.callbacks.device_to_logical(point)
+    }
+
+    // When false, nothing is animating and no timer is pending, so the
+    // embedder can block until the EventLoopWaker fires. Servo's
+    // compositor keeps its animation state to itself, so this fails with
+    // `ServoError::Unsupported` for now.
+    pub fn is_animating(&self) -> Result<bool, ServoError> {
+        Err(ServoError::Unsupported)
+    }
 
-pub struct Constellation {
-}
+    // Nothing is composited while suspended, for any browser. Servo still
+    // handles events and runs scripts: it can't pause timers yet.
+    pub fn suspend(&self) {
+        self.callbacks.suspended.set(true);
+    }
+    pub fn resume(&self) {
+        if self.callbacks.suspended.get() {
+            self.callbacks.suspended.set(false);
+            self.force_repaint();
+        }
+    }
 
-pub struct Compositor {
-    servo: RefCell<Servo<WindowCallback>>,
-    callbacks: Rc<WindowCallback>,
-}
+    // A snapshot of the whole Servo process. Gathering it walks every
+    // heap, so it's not something to call every frame. Servo's memory
+    // profiler isn't reachable from here yet.
+    pub fn memory_report(&self) -> Result<MemoryReport, ServoError> {
+        Err(ServoError::Unsupported)
+    }
 
-pub struct View {
-}
+    // Decoded through Servo's image cache, to reuse its cookies and cached
+    // copy. That cache isn't exposed to embedders yet, so this fails with
+    // `ServoError::Unsupported`.
+    pub fn fetch_favicon(&self, _url: ServoUrl) -> Result<Receiver<Result<FaviconImage, ServoError>>, ServoError> {
+        Err(ServoError::Unsupported)
+    }
 
-impl View {
-    pub fn show(&self, _: Option<BrowserId>) {
+    // Servo has no editing commands, these go through the same shortcuts
+    // web content sees from a keyboard. Key events reach the focused
+    // browser only, others fail with `ServoError::NotFocused`.
+    pub fn copy_selection(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.send_shortcut(id, 'c', Key::C)
+    }
+    pub fn cut_selection(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.send_shortcut(id, 'x', Key::X)
+    }
+    pub fn paste(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.send_shortcut(id, 'v', Key::V)
+    }
+    pub fn select_all(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.send_shortcut(id, 'a', Key::A)
+    }
+    // Asks the ClipboardProvider, so it's as cheap as its get_text.
+    pub fn can_paste(&self) -> bool {
+        self.callbacks.can_paste()
     }
-}
 
-struct WindowCallback {
-    gl_methods: Rc<GLMethods>,
-    waker: Box<EventLoopWaker + 'static + Send>,
-    event_queue: RefCell<Vec<BrowserEvent>>,
-    pub geometry: Cell<DrawableGeometry>,
-}
+    // Prefs are process wide: this applies to every compositor, from the
+    // next load on. Images already loaded stay. Fails with
+    // `ServoError::Unsupported` if this Servo has no such pref.
+    pub fn set_images_enabled(&self, enabled: bool) -> Result<(), ServoError> {
+        set_runtime_pref("network.images.enabled", PrefValue::Bool(enabled))
+    }
 
-impl Constellation {
-    pub fn new() -> Result<Constellation, &'static str> {
-        let path = env::current_dir().unwrap().join("servo_resources/");
-        if !path.exists() {
-            return Err("Can't find servo_resources/ directory");
-        }
-        let path = path.to_str().unwrap().to_string();
-        set_resources_path(Some(path));
-        Ok(Constellation {})
+    // Servo has no per-browser script setting. `id` is only checked: the
+    // pref applies to every browser of every compositor, for documents
+    // created afterwards. Pages already running keep their scripts.
+    pub fn set_javascript_enabled(&self, id: BrowserId, enabled: bool) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        set_runtime_pref("dom.script.enabled", PrefValue::Bool(enabled))
     }
 
-    pub fn new_compositor(&self, gl_methods: Rc<GLMethods>, waker: Box<EventLoopWaker + Send>, geometry: DrawableGeometry) -> Compositor {
-        let cb = Rc::new(WindowCallback {
-            gl_methods: gl_methods.clone(),
-            waker: waker,
-            geometry: Cell::new(geometry),
-            event_queue: RefCell::new(Vec::new()),
-        });
-        Compositor {
-            servo: RefCell::new(Servo::new(cb.clone())),
-            callbacks: cb.clone(),
-        }
+    // Would apply to the current document and the next ones of `id`.
+    // Servo only takes user stylesheets from its startup options, for
+    // every browser at once, so these fail with `ServoError::Unsupported`.
+    pub fn add_user_stylesheet(&self, id: BrowserId, _css: String) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
+    }
+    pub fn clear_user_stylesheets(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        Err(ServoError::Unsupported)
     }
 
-    pub fn new_browser(&self, url: ServoUrl, compositor: &Compositor /*temporary*/) -> Result<BrowserId,()> {
-        compositor.new_browser(url)
+    pub fn set_background_color(&self, rgba: [f32; 4]) {
+        self.callbacks.background_color.set(rgba);
     }
-}
 
-impl Compositor {
-    pub fn new_view(&self, geometry: DrawableGeometry) -> View {
-        self.callbacks.geometry.set(geometry);
-        View { }
+    // Would re-evaluate `prefers-color-scheme` media queries in loaded
+    // pages. Servo's style system doesn't know that media feature yet.
+    pub fn set_color_scheme(&self, _scheme: ColorScheme) -> Result<(), ServoError> {
+        Err(ServoError::Unsupported)
+    }
+
+    // Composites again even if nothing changed, for when the native
+    // surface lost its content (window uncovered, restored…).
+    pub fn force_repaint(&self) {
+        self.handle_event(WindowEvent::Refresh);
     }
-    pub fn new_browser(&self, url: ServoUrl) -> Result<BrowserId,()> {
-        self.servo.borrow().create_browser(url)
+
+    // Servo keeps the gl::Gl and the GL resources it got at startup, so
+    // the new context has to share them with the lost one, or be the
+    // same context once restored.
+    pub fn reinitialize_gl(&self, gl_methods: Rc<GLMethods>) -> Result<(), ServoError> {
+        gl_methods
+            .make_current()
+            .map_err(|_| ServoError::GlContextUnavailable)?;
+        *self.callbacks.gl_methods.borrow_mut() = gl_methods;
+        self.callbacks.gl_context_lost.set(false);
+        self.force_repaint();
+        Ok(())
     }
-    pub fn show(&self, id: Option<BrowserId>) {
-        self.servo.borrow().select_browser(id.unwrap());
+
+    // Reads the last frame back from the current framebuffer, long after
+    // present swapped it. Only offscreen contexts, or windows that
+    // preserve their back buffer across swaps, still hold that frame: on
+    // other double-buffered windows the pixels are undefined. An empty
+    // view, like a minimized window, gives an empty image.
+    pub fn capture_frame(&self) -> Result<FrameImage, ServoError> {
+        if !self.callbacks.frame_presented.get() {
+            return Err(ServoError::NoFrame);
+        }
+        let size = self.callbacks.framebuffer_size();
+        let (width, height) = (size.width, size.height);
+        if width == 0 || height == 0 {
+            return Ok(FrameImage {
+                width: width,
+                height: height,
+                pixels: vec![],
+            });
+        }
+        self.callbacks.gl_methods
+            .borrow()
+            .make_current()
+            .map_err(|_| {
+                warn!("Can't make the GL context current to capture a frame");
+                ServoError::GlContextUnavailable
+            })?;
+        let gl = self.callbacks.gl_methods.borrow().get_gl();
+        let pixels = gl.read_pixels(0, 0, width as gl::GLsizei, height as gl::GLsizei, gl::RGBA, gl::UNSIGNED_BYTE);
+
+        // GL's origin is bottom-left.
+        let stride = width as usize * 4;
+        let mut flipped = Vec::with_capacity(pixels.len());
+        for row in pixels.chunks(stride).rev() {
+            flipped.extend_from_slice(row);
+        }
+
+        Ok(FrameImage {
+            width: width,
+            height: height,
+            pixels: flipped,
+        })
     }
 
+    pub fn handle(&self) -> CompositorHandle {
+        CompositorHandle {
+            sender: self.command_sender.clone(),
+            waker: self.callbacks.waker.clone(),
+        }
+    }
     pub fn perform_updates(&self) {
-        self.servo.borrow_mut().handle_events(vec![]);
+        self.run_handle_commands();
+        self.apply_pending_resize(false);
+        self.callbacks.update_load_progress();
+        self.send_events(vec![]);
     }
     pub fn get_events(&self) -> Vec<BrowserEvent> {
         self.callbacks.get_events()
     }
+    // Pending events, left in the queue.
+    pub fn peek_events(&self) -> Vec<BrowserEvent> {
+        self.callbacks.event_queue.borrow().iter().cloned().collect()
+    }
+    pub fn has_events(&self) -> bool {
+        !self.callbacks.event_queue.borrow().is_empty()
+    }
+    // Same as get_events, without collecting into a new Vec.
+    pub fn drain_events(&self) -> DrainEvents {
+        self.callbacks.drain_events()
+    }
+    // Pumps Servo until `predicate` accepts the events collected so far.
+    // Meant for tests: it polls, and sees nothing if an event handler is set.
+    pub fn run_until<F: Fn(&[BrowserEvent]) -> bool>(&self, predicate: F, timeout: Duration) -> Result<Vec<BrowserEvent>, Timeout> {
+        let start = Instant::now();
+        let mut events = vec![];
+        loop {
+            self.perform_updates();
+            events.extend(self.get_events());
+            if predicate(&events) {
+                return Ok(events);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Timeout { events: events });
+            }
+            thread::sleep(Duration::from_millis(RUN_UNTIL_POLL_INTERVAL_MS));
+        }
+    }
+    // Once a handler is set, events are not queued for get_events anymore.
+    // They are delivered as soon as Servo hands control back, so the
+    // handler is free to call into the compositor.
+    pub fn set_event_handler(&self, handler: Box<FnMut(BrowserEvent)>) {
+        *self.callbacks.event_handler.borrow_mut() = Some(handler);
+        self.callbacks.dispatch_events();
+    }
     pub fn handle_event(&self, event: WindowEvent) {
-        self.servo.borrow_mut().handle_events(vec![event]);
+        self.send_events(vec![event]);
+    }
+    // Blocks until Servo's threads are gone. Pending events are dropped.
+    // Views keep Servo alive: drop them first, or threads can't be joined.
+    pub fn shutdown(self) {
+        info!("Shutting down");
+        let mut running = self.servo.borrow_mut().handle_events(vec![WindowEvent::Quit]);
+        while running {
+            thread::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS));
+            running = self.servo.borrow_mut().handle_events(vec![]);
+        }
+        self.callbacks.event_queue.borrow_mut().clear();
+        match Rc::try_unwrap(self.servo) {
+            Ok(servo) => servo.into_inner().deinit(),
+            Err(_) => warn!("Views are still alive, Servo threads won't be joined"),
+        }
+    }
+
+    // Every key event goes through here, so reserved shortcuts are taken
+    // out before Servo sees them.
+    fn send_events(&self, events: Vec<WindowEvent>) {
+        let mut forwarded = Vec::with_capacity(events.len());
+        {
+            let reserved = self.reserved_shortcuts.borrow();
+            for event in events {
+                match event {
+                    WindowEvent::KeyEvent(ch, key, state, mods) if reserved.contains(&(key, mods)) => {
+                        if state == KeyState::Pressed {
+                            let id = self.focused_browser();
+                            self.callbacks.push_event(BrowserEvent::Key(id, ch, key, mods));
+                        }
+                    }
+                    event => forwarded.push(event),
+                }
+            }
+        }
+        send_to_servo(&self.servo, &self.callbacks, forwarded);
+    }
+
+    fn run_handle_commands(&self) {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                HandleCommand::LoadUrl(id, url) => {
+                    if let Err(error) = self.load_url(id, url) {
+                        warn!("Can't load URL from a handle: {}", error);
+                    }
+                }
+                HandleCommand::Reload(id) => {
+                    if let Err(error) = self.reload(id, false) {
+                        warn!("Can't reload from a handle: {}", error);
+                    }
+                }
+                HandleCommand::Resize(geometry) => self.resize(geometry),
+            }
+        }
+    }
+
+    fn apply_pending_resize(&self, now: bool) {
+        let (view, geometry, requested) = match self.callbacks.pending_resize.get() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let interval = self.resize_debounce.get().unwrap_or(Duration::from_millis(0));
+        if !now && requested.elapsed() < interval {
+            return;
+        }
+        self.callbacks.pending_resize.set(None);
+        // Another view was shown in the meantime and Servo already uses
+        // its geometry.
+        if self.callbacks.active_view.get() == view {
+            if let Some(event) = update_geometry(&self.callbacks, geometry) {
+                self.send_events(vec![event]);
+            }
+        }
+    }
+
+    fn navigate(&self, id: BrowserId, direction: WindowNavigateMsg, steps: usize) -> bool {
+        if steps == 0 || self.check_browser(id).is_err() {
+            return false;
+        }
+        let events = (0..steps).map(|_| WindowEvent::Navigation(id, direction)).collect();
+        self.send_events(events);
+        true
+    }
+
+    // Logical coordinates, clamped to the view, to device pixels.
+    fn device_point(&self, x: f32, y: f32) -> TypedPoint2D<f32, DevicePixel> {
+        let (width, height) = self.callbacks.geometry.get().view_size;
+        let x = x.max(0.0).min(width as f32);
+        let y = y.max(0.0).min(height as f32);
+        let (x, y) = self.logical_to_device((x, y));
+        TypedPoint2D::new(x, y)
+    }
+
+    fn send_touch(&self, event_type: TouchEventType, id: u32, x: f32, y: f32) {
+        let point = self.device_point(x, y);
+        self.handle_event(WindowEvent::Touch(event_type, TouchId(id as i32), point));
+    }
+
+    fn check_focused(&self, id: BrowserId) -> Result<(), ServoError> {
+        self.check_browser(id)?;
+        if self.focused_browser() != Some(id) {
+            return Err(ServoError::NotFocused);
+        }
+        Ok(())
+    }
+
+    fn scroll_to_location(&self, location: ScrollLocation) {
+        let (width, height) = self.callbacks.geometry.get().view_size;
+        let cursor = self.device_point(width as f32 / 2.0, height as f32 / 2.0);
+        let cursor = TypedPoint2D::new(cursor.x as i32, cursor.y as i32);
+        self.handle_event(WindowEvent::Scroll(location, cursor, TouchEventType::Move));
+    }
+
+    fn send_shortcut(&self, id: BrowserId, ch: char, key: Key) -> Result<(), ServoError> {
+        self.check_focused(id)?;
+        let mods = if cfg!(target_os = "macos") { SUPER } else { CONTROL };
+        self.send_events(vec![
+            WindowEvent::KeyEvent(Some(ch), key, KeyState::Pressed, mods),
+            WindowEvent::KeyEvent(None, key, KeyState::Released, mods),
+        ]);
+        Ok(())
+    }
+
+    fn check_browser(&self, id: BrowserId) -> Result<(), ServoError> {
+        if self.browsers.borrow().contains(&id) {
+            Ok(())
+        } else {
+            Err(ServoError::UnknownBrowser)
+        }
+    }
+}
+
+// Unlike the builder, the embedder didn't pick the key, so a missing pref
+// means the feature isn't there.
+fn set_runtime_pref(key: &str, value: PrefValue) -> Result<(), ServoError> {
+    if let ServoPrefValue::Missing = *PREFS.get(key) {
+        return Err(ServoError::Unsupported);
     }
+    PREFS.set(key, value.into());
+    Ok(())
 }
 
+fn can_drop(event: &BrowserEvent) -> bool {
+    match *event {
+        BrowserEvent::CursorChanged(_) |
+        BrowserEvent::StatusChanged(..) |
+        BrowserEvent::LinkHovered(..) |
+        BrowserEvent::LoadProgress(..) |
+        BrowserEvent::ScrollPositionChanged(..) |
+        BrowserEvent::NetworkEvent(..) |
+        BrowserEvent::NewFrameReady(_) => true,
+        _ => false,
+    }
+}
+
+fn percent_encode(input: &str, output: &mut String) {
+    for byte in input.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                output.push(byte as char)
+            }
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+}
+
+// Right after the <head> start tag, or after a leading doctype, so the
+// page doesn't fall into quirks mode.
+fn base_insertion_point(html: &str) -> usize {
+    let lower = html.to_ascii_lowercase();
+    let tag_end = |start: usize| lower[start..].find('>').map_or(html.len(), |end| start + end + 1);
+    let head = lower.match_indices("<head").map(|(start, _)| start).find(|&start| {
+        match lower[start + 5..].chars().next() {
+            Some(ch) => ch == '>' || ch == '/' || ch.is_whitespace(),
+            None => false,
+        }
+    });
+    if let Some(start) = head {
+        return tag_end(start);
+    }
+    let trimmed = lower.trim_left();
+    if trimmed.starts_with("<!doctype") {
+        return tag_end(lower.len() - trimmed.len());
+    }
+    0
+}
+
+fn key_for_char(ch: char) -> Option<(Key, KeyModifiers)> {
+    let key = match ch.to_ascii_lowercase() {
+        'a' => Key::A, 'b' => Key::B, 'c' => Key::C, 'd' => Key::D, 'e' => Key::E,
+        'f' => Key::F, 'g' => Key::G, 'h' => Key::H, 'i' => Key::I, 'j' => Key::J,
+        'k' => Key::K, 'l' => Key::L, 'm' => Key::M, 'n' => Key::N, 'o' => Key::O,
+        'p' => Key::P, 'q' => Key::Q, 'r' => Key::R, 's' => Key::S, 't' => Key::T,
+        'u' => Key::U, 'v' => Key::V, 'w' => Key::W, 'x' => Key::X, 'y' => Key::Y,
+        'z' => Key::Z,
+        '0' | ')' => Key::Num0, '1' | '!' => Key::Num1, '2' | '@' => Key::Num2,
+        '3' | '#' => Key::Num3, '4' | '$' => Key::Num4, '5' | '%' => Key::Num5,
+        '6' | '^' => Key::Num6, '7' | '&' => Key::Num7, '8' | '*' => Key::Num8,
+        '9' | '(' => Key::Num9,
+        ' ' => Key::Space,
+        '\'' | '"' => Key::Apostrophe,
+        ',' | '<' => Key::Comma,
+        '-' | '_' => Key::Minus,
+        '.' | '>' => Key::Period,
+        '/' | '?' => Key::Slash,
+        ';' | ':' => Key::Semicolon,
+        '=' | '+' => Key::Equal,
+        '[' | '{' => Key::LeftBracket,
+        '\\' | '|' => Key::Backslash,
+        ']' | '}' => Key::RightBracket,
+        '`' | '~' => Key::GraveAccent,
+        '\n' => Key::Enter,
+        '\t' => Key::Tab,
+        _ => return None,
+    };
+    let shifted = ch.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(ch);
+    let mods = if shifted { SHIFT } else { NONE };
+    Some((key, mods))
+}
+
+fn to_device(length: u32, hidpi_factor: f32) -> u32 {
+    (length as f32 * hidpi_factor).round() as u32
+}
+
+fn framebuffer_size_for(geometry: &DrawableGeometry) -> TypedSize2D<u32, DevicePixel> {
+    let (width, height) = geometry.view_size;
+    TypedSize2D::new(to_device(width, geometry.hidpi_factor),
+                     to_device(height, geometry.hidpi_factor))
+}
+
+fn window_rect_for(geometry: &DrawableGeometry) -> TypedRect<u32, DevicePixel> {
+    let mut size = framebuffer_size_for(geometry);
+
+    let (top, right, bottom, left) = geometry.margins;
+    let top = to_device(top, geometry.hidpi_factor);
+    let right = to_device(right, geometry.hidpi_factor);
+    let bottom = to_device(bottom, geometry.hidpi_factor);
+    let left = to_device(left, geometry.hidpi_factor);
+
+    size.height = size.height.saturating_sub(top).saturating_sub(bottom);
+    size.width = size.width.saturating_sub(left).saturating_sub(right);
+
+    TypedRect::new(TypedPoint2D::new(left, top), size)
+}
+
+// Everything sent to Servo goes through here, so the events it queued
+// reach the event handler right away.
+fn send_to_servo(servo: &RefCell<Servo<WindowCallback>>, callbacks: &WindowCallback, events: Vec<WindowEvent>) {
+    servo.borrow_mut().handle_events(events);
+    callbacks.dispatch_events();
+}
+
+// Make `geometry` the one Servo renders with. Returns the Resize that
+// lets Servo reflow, unless nothing changed.
+fn update_geometry(callbacks: &WindowCallback, geometry: DrawableGeometry) -> Option<WindowEvent> {
+    if callbacks.geometry.get() == geometry {
+        return None;
+    }
+    callbacks.geometry.set(geometry);
+    Some(WindowEvent::Resize(framebuffer_size_for(&geometry)))
+}
+
+// Passing `None` leaves no browser selected, and the compositor paints
+// a blank frame until another browser is shown: the Refresh makes it
+// present that frame. Closed browsers can't be shown.
+fn select_browser(callbacks: &WindowCallback, id: Option<BrowserId>) -> Result<WindowEvent, ServoError> {
+    if let Some(id) = id {
+        if !callbacks.has_browser(id) {
+            return Err(ServoError::UnknownBrowser);
+        }
+    }
+    callbacks.selected_browser.set(id);
+    Ok(match id {
+        Some(id) => WindowEvent::SelectBrowser(id),
+        None => WindowEvent::Refresh,
+    })
+}
 
 impl WindowMethods for WindowCallback {
     fn prepare_for_composite(&self, _width: usize, _height: usize) -> bool {
-        self.gl_methods.make_current().is_ok()
+        if self.suspended.get() {
+            return false;
+        }
+        if !self.make_current() {
+            warn!("Can't make the GL context current, skipping composite");
+            return false;
+        }
+        let hidden = match self.selected_browser.get() {
+            Some(id) => self.browser_states.borrow().get(&id).map_or(false, |state| state.hidden),
+            None => false,
+        };
+        // Shows through until content paints.
+        let rgba = self.background_color.get();
+        let gl = self.gl_methods.borrow().get_gl();
+        gl.clear_color(rgba[0], rgba[1], rgba[2], rgba[3]);
+        gl.clear(gl::COLOR_BUFFER_BIT);
+        // A hidden browser gets a blank frame, without NewFrameReady.
+        if hidden {
+            self.gl_methods.borrow().swap_buffers();
+            return false;
+        }
+        if self.selected_browser.get().is_none() {
+            self.present();
+            return false;
+        }
+        true
     }
 
     fn supports_clipboard(&self) -> bool {
-        false
+        self.clipboard.is_some()
     }
 
     fn create_event_loop_waker(&self) -> Box<EventLoopWaker> {
@@ -154,7 +2041,7 @@ impl WindowMethods for WindowCallback {
     }
 
     fn gl(&self) -> Rc<gl::Gl> {
-        self.gl_methods.get_gl()
+        self.gl_methods.borrow().get_gl()
     }
 
     fn hidpi_factor(&self) -> ScaleFactor<f32, DeviceIndependentPixel, DevicePixel> {
@@ -163,25 +2050,11 @@ impl WindowMethods for WindowCallback {
     }
 
     fn framebuffer_size(&self) -> TypedSize2D<u32, DevicePixel> {
-        let scale_factor = self.geometry.get().hidpi_factor as u32;
-        let (width, height) = self.geometry.get().view_size;
-        TypedSize2D::new(scale_factor * width, scale_factor * height)
+        framebuffer_size_for(&self.geometry.get())
     }
 
     fn window_rect(&self) -> TypedRect<u32, DevicePixel> {
-        let scale_factor = self.geometry.get().hidpi_factor as u32;
-        let mut size = self.framebuffer_size();
-
-        let (top, right, bottom, left) = self.geometry.get().margins;
-        let top = top * scale_factor;
-        let right = right * scale_factor;
-        let bottom = bottom * scale_factor;
-        let left = left * scale_factor;
-
-        size.height = size.height - top - bottom;
-        size.width = size.width - left - right;
-
-        TypedRect::new(TypedPoint2D::new(left, top), size)
+        window_rect_for(&self.geometry.get())
     }
 
     fn size(&self) -> TypedSize2D<f32, DeviceIndependentPixel> {
@@ -198,98 +2071,563 @@ impl WindowMethods for WindowCallback {
     // Events
 
     fn set_inner_size(&self, id: BrowserId, size: Size2D<u32>) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::SetWindowInnerSize(id, size.width as u32, size.height as u32));
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::SetWindowInnerSize(id, size.width as u32, size.height as u32));
     }
 
     fn set_position(&self, id: BrowserId, point: Point2D<i32>) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::SetWindowPosition(id, point.x, point.y));
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::SetWindowPosition(id, point.x, point.y));
     }
 
     fn set_fullscreen_state(&self, id: BrowserId, state: bool) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::SetFullScreenState(id, state))
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::SetFullScreenState(id, state));
     }
 
     fn present(&self) {
-        self.gl_methods.swap_buffers();
+        self.gl_methods.borrow().swap_buffers();
+        self.frame_presented.set(true);
+        if let Some(id) = self.selected_browser.get() {
+            self.push_event(BrowserEvent::NewFrameReady(id));
+        }
     }
 
     fn set_page_title(&self, id: BrowserId, title: Option<String>) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::TitleChanged(id, title));
+        {
+            let mut states = self.browser_states.borrow_mut();
+            let state = match states.get_mut(&id) {
+                Some(state) => state,
+                None => return,
+            };
+            state.title = title.clone();
+            let current = state.current;
+            if let Some(entry) = state.history.get_mut(current) {
+                entry.title = title.clone();
+            }
+            if state.title_override.is_some() {
+                return;
+            }
+        }
+        self.push_event(BrowserEvent::TitleChanged(id, title));
     }
 
     fn status(&self, id: BrowserId, status: Option<String>) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::StatusChanged(id, status));
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::StatusChanged(id, status));
     }
 
     fn load_start(&self, id: BrowserId) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::LoadStart(id));
+        {
+            let mut states = self.browser_states.borrow_mut();
+            let state = match states.get_mut(&id) {
+                Some(state) => state,
+                None => return,
+            };
+            state.load_started = Some(Instant::now());
+            state.load_progress = 0.0;
+        }
+        self.push_event(BrowserEvent::LoadStart(id));
+        self.push_event(BrowserEvent::LoadProgress(id, 0.0));
     }
 
     fn load_end(&self, id: BrowserId) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::LoadEnd(id));
+        match self.browser_states.borrow_mut().get_mut(&id) {
+            Some(state) => {
+                state.load_started = None;
+                state.load_progress = 1.0;
+                for waiter in state.load_waiters.drain(..) {
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+            None => return,
+        }
+        self.push_event(BrowserEvent::LoadProgress(id, 1.0));
+        self.push_event(BrowserEvent::LoadEnd(id));
     }
 
-    fn load_error(&self, id: BrowserId, _: NetError, url: String) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::LoadError(id, url));
+    fn load_error(&self, id: BrowserId, error: NetErrorKind, url: String) {
+        match self.browser_states.borrow_mut().get_mut(&id) {
+            Some(state) => {
+                state.load_started = None;
+                for waiter in state.load_waiters.drain(..) {
+                    let _ = waiter.send(Err(LoadFailure::Network(error, url.clone())));
+                }
+            }
+            None => return,
+        }
+        warn!("Failed to load {} in {:?}: {:?}", url, id, error);
+        self.push_event(BrowserEvent::LoadError(id, error, url));
     }
 
     fn head_parsed(&self, id: BrowserId) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::HeadParsed(id));
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::HeadParsed(id));
     }
 
     fn history_changed(&self, id: BrowserId, entries: Vec<LoadData>, current: usize) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::HistoryChanged(id, entries, current));
+        let history = {
+            let mut states = self.browser_states.borrow_mut();
+            let state = match states.get_mut(&id) {
+                Some(state) => state,
+                None => return,
+            };
+            // LoadData has no title. Keep the ones we saw for entries that
+            // are still at the same place.
+            let history = entries.iter().enumerate().map(|(i, entry)| {
+                let title = state.history
+                    .get(i)
+                    .and_then(|old| if old.url == entry.url { old.title.clone() } else { None });
+                HistoryEntry {
+                    url: entry.url.clone(),
+                    title: title,
+                }
+            }).collect();
+            state.history = history;
+            state.current = current;
+            state.history.clone()
+        };
+        self.push_event(BrowserEvent::HistoryChanged(id, history, current));
     }
 
+    // Servo sets the cursor on every mouse move.
     fn set_cursor(&self, cursor: Cursor) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::CursorChanged(cursor));
+        if self.cursor.get() == Some(cursor) {
+            return;
+        }
+        self.cursor.set(Some(cursor));
+        self.push_event(BrowserEvent::CursorChanged(cursor));
     }
 
     fn set_favicon(&self, id: BrowserId, url: ServoUrl) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::FaviconChanged(id, url));
+        if !self.has_browser(id) {
+            return;
+        }
+        self.push_event(BrowserEvent::FaviconChanged(id, url));
     }
 
     fn allow_navigation(&self, id: BrowserId, url: ServoUrl, chan: IpcSender<bool>) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::AllowNavigation(id, url, chan));
+        if !self.has_browser(id) {
+            let _ = chan.send(false);
+            return;
+        }
+        self.push_event(BrowserEvent::AllowNavigation(id, url, chan));
     }
 
     fn handle_key(&self, id: Option<BrowserId>, ch: Option<char>, key: Key, mods: constellation_msg::KeyModifiers) {
-        self.event_queue
-            .borrow_mut()
-            .push(BrowserEvent::Key(id, ch, key, mods));
+        self.push_event(BrowserEvent::Key(id, ch, key, mods));
     }
 }
 
 impl WindowCallback {
-    pub fn get_events(&self) -> Vec<BrowserEvent> {
+    fn new(gl_methods: Rc<GLMethods>, waker: Box<EventLoopWaker + Send>, geometry: DrawableGeometry, clipboard: Option<Box<ClipboardProvider>>, event_queue_policy: EventQueuePolicy) -> WindowCallback {
+        WindowCallback {
+            gl_methods: RefCell::new(gl_methods),
+            gl_context_lost: Cell::new(false),
+            waker: waker,
+            clipboard: clipboard,
+            geometry: Cell::new(geometry),
+            event_queue: RefCell::new(VecDeque::new()),
+            event_queue_policy: event_queue_policy,
+            browser_states: RefCell::new(HashMap::new()),
+            selected_browser: Cell::new(None),
+            frame_presented: Cell::new(false),
+            suspended: Cell::new(false),
+            cursor: Cell::new(None),
+            background_color: Cell::new([1.0, 1.0, 1.0, 1.0]),
+            views: RefCell::new(HashMap::new()),
+            active_view: Cell::new(None),
+            next_view_id: Cell::new(0),
+            event_handler: RefCell::new(None),
+            pending_resize: Cell::new(None),
+        }
+    }
+
+    // Browsers get their state when created and lose it when closed.
+    // Servo can still report on a closed browser for a while.
+    fn has_browser(&self, id: BrowserId) -> bool {
+        self.browser_states.borrow().contains_key(&id)
+    }
+
+    // GlContextLost is sent on the first failure after a success.
+    fn make_current(&self) -> bool {
+        if self.gl_methods.borrow().make_current().is_err() {
+            if !self.gl_context_lost.get() {
+                self.gl_context_lost.set(true);
+                self.push_event(BrowserEvent::GlContextLost);
+            }
+            return false;
+        }
+        self.gl_context_lost.set(false);
+        true
+    }
+
+    fn logical_to_device(&self, point: (f32, f32)) -> (f32, f32) {
+        let scale_factor = self.geometry.get().hidpi_factor;
+        (point.0 * scale_factor, point.1 * scale_factor)
+    }
+
+    fn device_to_logical(&self, point: (f32, f32)) -> (f32, f32) {
+        let scale_factor = self.geometry.get().hidpi_factor;
+        (point.0 / scale_factor, point.1 / scale_factor)
+    }
+
+    fn update_load_progress(&self) {
+        let mut states = self.browser_states.borrow_mut();
+        for (&id, state) in states.iter_mut() {
+            let started = match state.load_started {
+                Some(started) => started,
+                None => continue,
+            };
+            let elapsed = started.elapsed();
+            let secs = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+            let progress = MAX_LOAD_PROGRESS * (1.0 - (-secs / LOAD_PROGRESS_TIME_CONSTANT).exp());
+            if progress - state.load_progress >= LOAD_PROGRESS_STEP {
+                state.load_progress = progress;
+                self.push_event(BrowserEvent::LoadProgress(id, progress));
+            }
+        }
+    }
+
+    fn push_event(&self, event: BrowserEvent) {
         let mut events = self.event_queue.borrow_mut();
-        let copy = events.drain(..).collect();
-        copy
+        let policy = self.event_queue_policy;
+        if policy.coalesce {
+            let superseded = match (events.back(), &event) {
+                (Some(&BrowserEvent::CursorChanged(_)), &BrowserEvent::CursorChanged(_)) => true,
+                (Some(&BrowserEvent::StatusChanged(a, _)), &BrowserEvent::StatusChanged(b, _)) => a == b,
+                (Some(&BrowserEvent::LinkHovered(a, _)), &BrowserEvent::LinkHovered(b, _)) => a == b,
+                _ => false,
+            };
+            if superseded {
+                events.pop_back();
+            }
+        }
+        events.push_back(event);
+        if let Some(max) = policy.max_events {
+            if events.len() > max {
+                match events.iter().position(can_drop) {
+                    Some(index) => {
+                        events.remove(index);
+                    }
+                    None => warn!("Event queue is over its limit with nothing to drop"),
+                }
+            }
+        }
+    }
+
+    pub fn get_events(&self) -> Vec<BrowserEvent> {
+        self.event_queue.borrow_mut().drain(..).collect()
+    }
+
+    fn drain_events(&self) -> DrainEvents {
+        DrainEvents { queue: &self.event_queue }
+    }
+
+    fn can_paste(&self) -> bool {
+        self.clipboard
+            .as_ref()
+            .and_then(|clipboard| clipboard.get_text())
+            .map_or(false, |text| !text.is_empty())
+    }
+
+    fn dispatch_events(&self) {
+        // The handler is taken out while it runs. If it re-enters the
+        // compositor, the nested dispatch is a no-op and the events it
+        // produced are picked up by the loop below.
+        let mut handler = match self.event_handler.borrow_mut().take() {
+            Some(handler) => handler,
+            None => return,
+        };
+        for event in self.drain_events() {
+            trace!("Dispatching {:?}", event);
+            handler(event);
+        }
+        let mut slot = self.event_handler.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(handler);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::{Bencher, black_box};
+
+    // drain_events and get_events, for the same 1000 events.
+    #[bench]
+    fn drain_events_reuses_the_queue(b: &mut Bencher) {
+        let callbacks = callbacks(EventQueuePolicy::default());
+        b.iter(|| {
+            for _ in 0..1000 {
+                callbacks.push_event(BrowserEvent::GlContextLost);
+            }
+            for event in callbacks.drain_events() {
+                black_box(event);
+            }
+        });
+    }
+
+    #[bench]
+    fn get_events_collects_a_vec(b: &mut Bencher) {
+        let callbacks = callbacks(EventQueuePolicy::default());
+        b.iter(|| {
+            for _ in 0..1000 {
+                callbacks.push_event(BrowserEvent::GlContextLost);
+            }
+            for event in callbacks.get_events() {
+                black_box(event);
+            }
+        });
+    }
+
+    #[test]
+    fn drain_events_empties_the_queue() {
+        let callbacks = callbacks(EventQueuePolicy::default());
+        for _ in 0..3 {
+            callbacks.push_event(BrowserEvent::GlContextLost);
+        }
+        assert_eq!(callbacks.drain_events().count(), 3);
+        assert!(callbacks.event_queue.borrow().is_empty());
+    }
+
+    #[test]
+    fn key_for_char_shifts_uppercase_and_symbols() {
+        assert_eq!(key_for_char('h'), Some((Key::H, NONE)));
+        assert_eq!(key_for_char('H'), Some((Key::H, SHIFT)));
+        assert_eq!(key_for_char('1'), Some((Key::Num1, NONE)));
+        assert_eq!(key_for_char('!'), Some((Key::Num1, SHIFT)));
+        assert_eq!(key_for_char(' '), Some((Key::Space, NONE)));
+        assert_eq!(key_for_char('\n'), Some((Key::Enter, NONE)));
+    }
+
+    #[test]
+    fn key_for_char_skips_chars_without_a_key() {
+        assert_eq!(key_for_char('é'), None);
+        assert_eq!(key_for_char('€'), None);
+    }
+
+    fn geometry(view_size: (u32, u32), margins: (u32, u32, u32, u32), hidpi_factor: f32) -> DrawableGeometry {
+        DrawableGeometry {
+            view_size: view_size,
+            margins: margins,
+            position: (0, 0),
+            hidpi_factor: hidpi_factor,
+        }
+    }
+
+    #[test]
+    fn window_rect_is_empty_when_margins_exceed_the_view() {
+        let rect = window_rect_for(&geometry((100, 50), (40, 80, 40, 80), 1.0));
+        assert_eq!((rect.origin.x, rect.origin.y), (80, 40));
+        assert_eq!((rect.size.width, rect.size.height), (0, 0));
+    }
+
+    #[test]
+    fn framebuffer_size_rounds_fractional_hidpi_factors() {
+        let sizes: Vec<_> = [1.25, 1.5, 2.0].iter().map(|&factor| {
+            let size = framebuffer_size_for(&geometry((801, 601), (0, 0, 0, 0), factor));
+            (size.width, size.height)
+        }).collect();
+        assert_eq!(sizes, vec![(1001, 751), (1202, 902), (1602, 1202)]);
+    }
+
+    #[test]
+    fn window_rect_scales_margins_with_the_hidpi_factor() {
+        let rect = window_rect_for(&geometry((800, 600), (10, 0, 0, 0), 1.5));
+        assert_eq!((rect.origin.x, rect.origin.y), (0, 15));
+        assert_eq!((rect.size.width, rect.size.height), (1200, 885));
+    }
+
+    #[test]
+    fn percent_encode_keeps_only_unreserved_bytes() {
+        let mut url = String::from("data:text/html;charset=utf-8,");
+        percent_encode("<p class=\"a\">é & ~x-y_z.</p>", &mut url);
+        assert_eq!(url, "data:text/html;charset=utf-8,\
+                         %3Cp%20class%3D%22a%22%3E%C3%A9%20%26%20~x-y_z.%3C%2Fp%3E");
+    }
+
+    #[test]
+    fn geometries_compare_by_value() {
+        assert_eq!(geometry((800, 600), (10, 0, 0, 0), 2.0), geometry((800, 600), (10, 0, 0, 0), 2.0));
+        assert!(geometry((800, 600), (10, 0, 0, 0), 2.0) != geometry((800, 600), (10, 0, 0, 0), 1.0));
+    }
+
+    // Enough for callbacks that never touch GL.
+    struct TestGL;
+
+    impl GLMethods for TestGL {
+        fn make_current(&self) -> Result<(),()> {
+            Ok(())
+        }
+        fn swap_buffers(&self) {
+        }
+        fn get_gl(&self) -> Rc<gl::Gl> {
+            unreachable!()
+        }
+    }
+
+    fn callbacks(policy: EventQueuePolicy) -> WindowCallback {
+        let geometry = geometry((800, 600), (0, 0, 0, 0), 1.0);
+        WindowCallback::new(Rc::new(TestGL), box HeadlessWaker, geometry, None, policy)
+    }
+
+    fn callbacks_with(gl_methods: Rc<GLMethods>, clipboard: Option<Box<ClipboardProvider>>) -> WindowCallback {
+        let geometry = geometry((800, 600), (0, 0, 0, 0), 1.0);
+        WindowCallback::new(gl_methods, box HeadlessWaker, geometry, clipboard, EventQueuePolicy::default())
+    }
+
+    fn cursor(keyword: &str) -> Cursor {
+        Cursor::from_css_keyword(keyword).unwrap()
+    }
+
+    #[test]
+    fn event_queue_stays_bounded_and_keeps_what_cant_be_dropped() {
+        let callbacks = callbacks(EventQueuePolicy {
+            max_events: Some(10),
+            coalesce: false,
+        });
+        callbacks.push_event(BrowserEvent::GlContextLost);
+        for i in 0..5000 {
+            let cursor = cursor(if i % 2 == 0 { "pointer" } else { "text" });
+            callbacks.push_event(BrowserEvent::CursorChanged(cursor));
+        }
+        let events = callbacks.get_events();
+        assert_eq!(events.len(), 10);
+        match events[0] {
+            BrowserEvent::GlContextLost => {}
+            ref event => panic!("Unexpected {:?}", event),
+        }
+    }
+
+    #[test]
+    fn event_queue_coalesces_consecutive_cursors() {
+        let callbacks = callbacks(EventQueuePolicy {
+            max_events: None,
+            coalesce: true,
+        });
+        callbacks.push_event(BrowserEvent::CursorChanged(cursor("pointer")));
+        callbacks.push_event(BrowserEvent::CursorChanged(cursor("text")));
+        callbacks.push_event(BrowserEvent::GlContextLost);
+        callbacks.push_event(BrowserEvent::CursorChanged(cursor("move")));
+        let cursors: Vec<_> = callbacks.get_events().into_iter().filter_map(|event| match event {
+            BrowserEvent::CursorChanged(cursor) => Some(cursor),
+            _ => None,
+        }).collect();
+        assert_eq!(cursors, vec![cursor("text"), cursor("move")]);
+    }
+
+    #[test]
+    fn set_cursor_skips_repeated_cursors() {
+        let callbacks = callbacks(EventQueuePolicy::default());
+        for keyword in &["pointer", "pointer", "pointer", "text", "text", "pointer"] {
+            callbacks.set_cursor(cursor(keyword));
+        }
+        let cursors: Vec<_> = callbacks.get_events().into_iter().filter_map(|event| match event {
+            BrowserEvent::CursorChanged(cursor) => Some(cursor),
+            _ => None,
+        }).collect();
+        assert_eq!(cursors, vec![cursor("pointer"), cursor("text"), cursor("pointer")]);
+    }
+
+    #[test]
+    fn logical_and_device_points_round_trip() {
+        let callbacks = callbacks(EventQueuePolicy::default());
+        callbacks.geometry.set(geometry((800, 600), (0, 0, 0, 0), 2.0));
+        assert_eq!(callbacks.logical_to_device((10.5, 300.0)), (21.0, 600.0));
+        assert_eq!(callbacks.device_to_logical((21.0, 600.0)), (10.5, 300.0));
+        let point = (123.25, 0.75);
+        assert_eq!(callbacks.device_to_logical(callbacks.logical_to_device(point)), point);
+    }
+
+    #[test]
+    fn base_goes_after_the_head_tag_or_the_doctype() {
+        assert_eq!(base_insertion_point("<p>hi</p>"), 0);
+        assert_eq!(base_insertion_point("  <!DOCTYPE html><p>hi</p>"), 17);
+        assert_eq!(base_insertion_point("<!doctype html><html><HEAD><title>"), 27);
+        assert_eq!(base_insertion_point("<head lang=\"en\"><header>"), 16);
+        assert_eq!(base_insertion_point("<header>x</header>"), 0);
+    }
+
+    struct TestClipboard(Option<&'static str>);
+
+    impl ClipboardProvider for TestClipboard {
+        fn get_text(&self) -> Option<String> {
+            self.0.map(String::from)
+        }
+        fn set_text(&self, _text: String) {
+        }
+    }
+
+    #[test]
+    fn can_paste_needs_text_on_the_clipboard() {
+        let can_paste = |clipboard: Option<Box<ClipboardProvider>>| callbacks_with(Rc::new(TestGL), clipboard).can_paste();
+        assert!(can_paste(Some(box TestClipboard(Some("x")))));
+        assert!(!can_paste(Some(box TestClipboard(Some("")))));
+        assert!(!can_paste(Some(box TestClipboard(None))));
+        assert!(!can_paste(None));
+    }
+
+    // A context that can be lost on demand, counting presented frames.
+    #[derive(Default)]
+    struct FlakyGL {
+        lost: Cell<bool>,
+        swaps: Cell<usize>,
+    }
+
+    impl GLMethods for FlakyGL {
+        fn make_current(&self) -> Result<(),()> {
+            if self.lost.get() { Err(()) } else { Ok(()) }
+        }
+        fn swap_buffers(&self) {
+            self.swaps.set(self.swaps.get() + 1);
+        }
+        fn get_gl(&self) -> Rc<gl::Gl> {
+            unreachable!()
+        }
+    }
+
+    fn context_losses(callbacks: &WindowCallback) -> usize {
+        callbacks.get_events().into_iter().filter(|event| match *event {
+            BrowserEvent::GlContextLost => true,
+            _ => false,
+        }).count()
+    }
+
+    #[test]
+    fn gl_context_loss_is_reported_once_until_it_recovers() {
+        let gl = Rc::new(FlakyGL::default());
+        let callbacks = callbacks_with(gl.clone(), None);
+        gl.lost.set(true);
+        assert!(!callbacks.prepare_for_composite(0, 0));
+        assert!(!callbacks.prepare_for_composite(0, 0));
+        assert_eq!(context_losses(&callbacks), 1);
+        // Compositing past make_current needs a real gl::Gl.
+        gl.lost.set(false);
+        assert!(callbacks.make_current());
+        gl.lost.set(true);
+        assert!(!callbacks.prepare_for_composite(0, 0));
+        assert_eq!(context_losses(&callbacks), 1);
+    }
+
+    #[test]
+    fn nothing_is_presented_while_suspended() {
+        let gl = Rc::new(FlakyGL::default());
+        let callbacks = callbacks_with(gl.clone(), None);
+        callbacks.suspended.set(true);
+        assert!(!callbacks.prepare_for_composite(0, 0));
+        assert_eq!(gl.swaps.get(), 0);
+        assert_eq!(callbacks.get_events().len(), 0);
     }
 }